@@ -1,11 +1,29 @@
 use regex::Regex;
 use std::error::Error;
+use std::fs::File;
+use std::io::Read;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+use toml;
+
+/// Default liveness-probe timeout applied to a target when none is configured.
+const DEFAULT_HEALTH_TIMEOUT: Duration = Duration::from_secs(2);
 
 #[derive(Clone, Debug)]
 pub struct Target {
     name: String,
     address: String,
     pattern: Option<Regex>,
+    host: Option<String>,
+    path_prefix: Option<String>,
+    // Shared so that the background liveness probe and every per-connection
+    // `ReverseProxy` clone observe the same health state.
+    healthy: Arc<AtomicBool>,
+    // Whether this target should be liveness-probed, and how long each probe
+    // waits for a response before the target is marked unhealthy.
+    health_check: bool,
+    health_timeout: Duration,
 }
 
 impl Target {
@@ -14,49 +32,203 @@ impl Target {
             name: name.into(),
             address: address.into(),
             pattern: pattern,
+            host: None,
+            path_prefix: None,
+            healthy: Arc::new(AtomicBool::new(true)),
+            health_check: true,
+            health_timeout: DEFAULT_HEALTH_TIMEOUT,
         }
     }
 
+    pub fn name(&self) -> &str {
+        self.name.as_ref()
+    }
+
     pub fn address(&self) -> &str {
         self.address.as_ref()
     }
+
+    /// Whether the background liveness probe currently considers this target up.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    /// A shared handle to this target's health flag, for the probe to update.
+    pub fn health_handle(&self) -> Arc<AtomicBool> {
+        Arc::clone(&self.healthy)
+    }
+
+    /// Whether this target should be liveness-probed at all.
+    pub fn health_check(&self) -> bool {
+        self.health_check
+    }
+
+    /// How long a liveness probe waits for a response from this target.
+    pub fn health_timeout(&self) -> Duration {
+        self.health_timeout
+    }
+
+    /// Returns whether this target should handle a request for the given path
+    /// and `Host` header.
+    ///
+    /// Every condition that the target specifies must hold: a configured host
+    /// must equal the incoming one, a configured path prefix must be a prefix
+    /// of the path, and a configured pattern must match the path. Conditions
+    /// left unset are ignored, so a target with nothing set matches everything.
+    pub fn valid_for(&self, path: &str, host: Option<&str>) -> bool {
+        if let Some(ref expected) = self.host {
+            // The HTTP `Host` is case-insensitive, so compare accordingly.
+            match host {
+                Some(actual) if actual.eq_ignore_ascii_case(expected) => {}
+                _ => return false,
+            }
+        }
+
+        if let Some(ref prefix) = self.path_prefix {
+            if !path.starts_with(prefix.as_str()) {
+                return false;
+            }
+        }
+
+        match self.pattern {
+            Some(ref pattern) => pattern.is_match(path),
+            None => true,
+        }
+    }
 }
 
 pub fn parse_target<S: Into<String>>(v: S) -> Result<Target, String> {
-    // expected format is "name at target_url if regex_pattern"
-    // only target_url is strictly required
+    // expected format is "name at target_url if regex_pattern host vhost prefix /p"
+    // only target_url is strictly required, and the trailing clauses may appear
+    // in any order
     let literal_at = " at ";
     let literal_if = " if ";
+    let literal_host = " host ";
+    let literal_prefix = " prefix ";
 
     let mut name = None;
-    let mut address = v.into();
-    if let Some(at_pos) = address.find(literal_at) {
-        name = Some(address[0..at_pos].into());
-        address = address[at_pos + literal_at.len()..].into();
+    let mut rest = v.into();
+    if let Some(at_pos) = rest.find(literal_at) {
+        name = Some(rest[0..at_pos].into());
+        rest = rest[at_pos + literal_at.len()..].into();
     }
 
+    // Locate each trailing clause keyword. The address is whatever precedes the
+    // first of them, and each clause runs up to the next keyword.
+    let mut marks: Vec<(usize, &str)> = [literal_if, literal_host, literal_prefix]
+        .iter()
+        .filter_map(|&kw| rest.find(kw).map(|pos| (pos, kw)))
+        .collect();
+    marks.sort_by_key(|&(pos, _)| pos);
+
+    let address: String = match marks.first() {
+        Some(&(pos, _)) => rest[0..pos].into(),
+        None => rest.clone(),
+    };
+
     let mut pattern = None;
-    if let Some(if_pos) = address.find(literal_if) {
-        pattern = {
-            let raw = &address[if_pos + literal_if.len()..];
-            Some(Regex::new(raw).map_err(|e| {
-                format!(
-                    "The text '{}' after '{}' is not a valid regular expression: {}",
-                    raw,
-                    literal_if,
-                    e.description()
-                ).to_owned()
-            })?)
-        };
-
-        address = address[0..if_pos].into();
-    }
-
-    Ok(Target::new(
-        name.unwrap_or(address.clone()),
-        address,
-        pattern,
-    ))
+    let mut host = None;
+    let mut path_prefix = None;
+    for (i, &(pos, kw)) in marks.iter().enumerate() {
+        let value_start = pos + kw.len();
+        let value_end = marks.get(i + 1).map(|&(next, _)| next).unwrap_or(rest.len());
+        let raw = &rest[value_start..value_end];
+
+        match kw {
+            _ if kw == literal_if => {
+                pattern = Some(Regex::new(raw).map_err(|e| {
+                    format!(
+                        "The text '{}' after '{}' is not a valid regular expression: {}",
+                        raw,
+                        literal_if,
+                        e.description()
+                    ).to_owned()
+                })?);
+            }
+            _ if kw == literal_host => host = Some(raw.to_owned()),
+            _ => path_prefix = Some(raw.to_owned()),
+        }
+    }
+
+    let mut target = Target::new(name.unwrap_or(address.clone()), address, pattern);
+    target.host = host;
+    target.path_prefix = path_prefix;
+    Ok(target)
+}
+
+/// The shape of a `--config` TOML file: the listen settings, debug flag, and
+/// the list of targets, all optional so CLI flags can override them.
+#[derive(Debug, Deserialize)]
+pub struct FileConfig {
+    pub interface: Option<String>,
+    pub port: Option<u16>,
+    pub debug: Option<bool>,
+    #[serde(default)]
+    pub targets: Vec<FileTarget>,
+}
+
+/// A single `[[targets]]` entry in a config file.
+#[derive(Debug, Deserialize)]
+pub struct FileTarget {
+    pub name: Option<String>,
+    pub address: String,
+    pub pattern: Option<String>,
+    pub host: Option<String>,
+    pub prefix: Option<String>,
+    pub health_check: Option<bool>,
+    pub health_timeout_secs: Option<u64>,
+}
+
+/// Read and parse a config file, reporting IO and syntax errors by path.
+pub fn load_config_file(path: &str) -> Result<FileConfig, String> {
+    let mut contents = String::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_string(&mut contents))
+        .map_err(|e| format!("Could not read config file '{}': {}", path, e.description()))?;
+
+    toml::from_str(&contents)
+        .map_err(|e| format!("Could not parse config file '{}': {}", path, e))
+}
+
+/// Build the `Target`s described by a config file.
+///
+/// Each entry is reassembled into the `"name at url if regex ..."` grammar and
+/// run through [`parse_target`] so that validation and regex-compilation error
+/// messages stay identical to the `--target` CLI path.
+pub fn targets_from_file(config: &FileConfig) -> Result<Vec<Target>, String> {
+    config
+        .targets
+        .iter()
+        .map(|entry| {
+            let mut spec = String::new();
+            if let Some(ref name) = entry.name {
+                spec.push_str(name);
+                spec.push_str(" at ");
+            }
+            spec.push_str(&entry.address);
+            if let Some(ref pattern) = entry.pattern {
+                spec.push_str(" if ");
+                spec.push_str(pattern);
+            }
+            if let Some(ref host) = entry.host {
+                spec.push_str(" host ");
+                spec.push_str(host);
+            }
+            if let Some(ref prefix) = entry.prefix {
+                spec.push_str(" prefix ");
+                spec.push_str(prefix);
+            }
+
+            let mut target = parse_target(spec)?;
+            if let Some(health_check) = entry.health_check {
+                target.health_check = health_check;
+            }
+            if let Some(secs) = entry.health_timeout_secs {
+                target.health_timeout = Duration::from_secs(secs);
+            }
+            Ok(target)
+        })
+        .collect()
 }
 
 #[cfg(test)]
@@ -99,6 +271,77 @@ mod tests {
         assert!(t.pattern.is_none());
     }
 
+    #[test]
+    fn parse_target_with_host_and_prefix_succeeds() {
+        let t = parse_target("api at http://127.0.0.1:9000 host api.localhost prefix /api/").unwrap();
+
+        assert_eq!(t.name, "api".to_owned());
+        assert_eq!(t.address, "http://127.0.0.1:9000".to_owned());
+        assert!(t.pattern.is_none());
+        assert_eq!(t.host, Some("api.localhost".to_owned()));
+        assert_eq!(t.path_prefix, Some("/api/".to_owned()));
+    }
+
+    #[test]
+    fn valid_for_respects_host_and_prefix() {
+        let t = parse_target("http://127.0.0.1:9000 host api.localhost prefix /api/").unwrap();
+
+        assert!(t.valid_for("/api/users", Some("api.localhost")));
+        assert!(t.valid_for("/api/users", Some("API.LocalHost")));
+        assert!(!t.valid_for("/api/users", Some("app.localhost")));
+        assert!(!t.valid_for("/", Some("api.localhost")));
+        assert!(!t.valid_for("/api/users", None));
+    }
+
+    #[test]
+    fn targets_from_file_reuses_parse_target() {
+        let raw = r#"
+interface = "0.0.0.0"
+port = 8080
+
+[[targets]]
+name = "api"
+address = "http://127.0.0.1:9000"
+host = "api.localhost"
+prefix = "/api/"
+health_timeout_secs = 1
+
+[[targets]]
+address = "http://127.0.0.1:9001"
+pattern = "^/$"
+health_check = false
+"#;
+
+        let file_config: FileConfig = toml::from_str(raw).unwrap();
+        assert_eq!(file_config.interface, Some("0.0.0.0".to_owned()));
+        assert_eq!(file_config.port, Some(8080));
+
+        let targets = targets_from_file(&file_config).unwrap();
+        assert_eq!(targets.len(), 2);
+
+        assert_eq!(targets[0].name, "api".to_owned());
+        assert_eq!(targets[0].host, Some("api.localhost".to_owned()));
+        assert_eq!(targets[0].path_prefix, Some("/api/".to_owned()));
+        assert_eq!(targets[0].health_timeout, Duration::from_secs(1));
+
+        assert_eq!(targets[1].pattern.as_ref().unwrap().as_str(), "^/$");
+        assert!(!targets[1].health_check);
+    }
+
+    #[test]
+    fn targets_from_file_reports_bad_regex_like_cli() {
+        let raw = r#"
+[[targets]]
+address = "http://127.0.0.1:9000"
+pattern = "*invalid"
+"#;
+
+        let file_config: FileConfig = toml::from_str(raw).unwrap();
+        let error = targets_from_file(&file_config).unwrap_err();
+
+        assert!(error.starts_with("The text '*invalid' after ' if ' is not a valid regular expression"));
+    }
+
     #[test]
     fn parse_target_with_bad_regex_fails() {
         let e = parse_target("http://127.0.0.1:9000 if *invalid").unwrap_err();
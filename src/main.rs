@@ -3,26 +3,66 @@ extern crate clap;
 extern crate futures;
 #[macro_use]
 extern crate hyper;
+#[cfg(feature = "tls")]
+extern crate hyper_tls;
 #[macro_use]
 extern crate lazy_static;
+extern crate regex;
+extern crate serde;
+#[macro_use]
+extern crate serde_derive;
+extern crate toml;
 extern crate tokio_core;
 extern crate unicase;
 
+mod config;
 mod proxy;
 
 use clap::{App, AppSettings, Arg};
+use config::{load_config_file, parse_target, targets_from_file, Target};
 use futures::Stream;
-use hyper::Client;
+use futures::future::{Either, Future};
+use hyper::{Client, Uri};
+use hyper::client::Connect;
 use hyper::server::Http;
 use proxy::ReverseProxy;
 use tokio_core::net::TcpListener;
-use tokio_core::reactor::Core;
+use tokio_core::reactor::{Core, Handle, Interval, Timeout};
 use std::net::SocketAddr;
 use std::error::Error;
+use std::sync::Arc;
+use std::sync::atomic::AtomicUsize;
+use std::time::Duration;
+
+/// How often each target is probed for liveness. The per-probe timeout is a
+/// per-target setting (see `Target::health_timeout`).
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[cfg(not(feature = "tls"))]
+use hyper::client::HttpConnector;
+#[cfg(feature = "tls")]
+use hyper_tls::HttpsConnector;
+
+/// Build the `Client` used to dispatch proxied requests.
+///
+/// By default this is a plain-HTTP client that can only reach `http://`
+/// targets. Compiling with the `tls` feature swaps in an HTTPS-capable
+/// connector so targets whose address begins with `https://` can be reached
+/// too, letting rocksy consolidate TLS backends behind its listen port.
+#[cfg(not(feature = "tls"))]
+fn build_client(handle: &Handle) -> Client<HttpConnector> {
+    Client::new(handle)
+}
+
+#[cfg(feature = "tls")]
+fn build_client(handle: &Handle) -> Client<HttpsConnector<hyper::client::HttpConnector>> {
+    let connector = HttpsConnector::new(4, handle).expect("could not build TLS connector");
+    Client::configure().connector(connector).build(handle)
+}
 
 fn run(config: Config) -> hyper::Result<()> {
     println!(
-        "Listening on {} and proxying to first of {:?}" //FIXME update output when logic is changed,
+        "Listening on {} and proxying to {:?}",
         &config.listen_addr, &config.targets
     );
 
@@ -30,9 +70,17 @@ fn run(config: Config) -> hyper::Result<()> {
     let mut core = Core::new()?;
     let handle = core.handle();
 
+    // Probe each backend in the background so unhealthy ones can be skipped
+    spawn_health_checks(&handle, build_client(&handle), config.targets.clone());
+
     // Listen to incoming requests over TCP, and forward them to a new `ReverseProxy`
     let listener = TcpListener::bind(&config.listen_addr, &handle)?;
     let http = Http::new();
+
+    // The round-robin counter is shared across every per-connection
+    // `ReverseProxy`, so load keeps spreading even when each client opens a
+    // fresh connection per request.
+    let counter = Arc::new(AtomicUsize::new(0));
     let server = listener.incoming().for_each(|(socket, addr)| {
         if config.debug {
             println!(
@@ -40,18 +88,10 @@ fn run(config: Config) -> hyper::Result<()> {
                 socket, addr
             )
         }
-        let client = Client::new(&handle);
-
-        //FIXME we should pass in a function which returns the correct Target, then pass that in
-        let target = config
-            .targets
-            .first()
-            .clone()
-            .expect("at least 1 target is guaranteed")
-            .address
-            .clone();
-
-        let service = ReverseProxy::new(client, Some(addr.ip()), target);
+        let client = build_client(&handle);
+
+        // The listener is a plain TCP socket, so the inbound connection is not TLS.
+        let service = ReverseProxy::new(client, Some(addr.ip()), config.targets.clone(), config.debug, false, Arc::clone(&counter));
         http.bind_connection(&handle, socket, addr, service);
         Ok(())
     });
@@ -62,6 +102,63 @@ fn run(config: Config) -> hyper::Result<()> {
     Ok(())
 }
 
+/// Spawn a periodic task on the reactor that probes the liveness of every
+/// target with health checking enabled, and flips its shared `healthy` flag
+/// based on whether a lightweight GET to the target's address returns within
+/// the target's health timeout. Targets with health checking disabled are not
+/// probed and keep their current flag.
+fn spawn_health_checks<C>(handle: &Handle, client: Client<C>, targets: Vec<Target>)
+where
+    C: 'static + Connect,
+{
+    let tick_handle = handle.clone();
+    let checks = Interval::new(HEALTH_CHECK_INTERVAL, handle)
+        .expect("could not create health-check interval")
+        .for_each(move |_| {
+            for target in &targets {
+                probe_target(&tick_handle, &client, target);
+            }
+            Ok(())
+        })
+        .map_err(|error| eprintln!("Health-check loop stopped: {}", error));
+
+    handle.spawn(checks);
+}
+
+/// Issue a single liveness probe for `target`, updating its health flag.
+fn probe_target<C>(handle: &Handle, client: &Client<C>, target: &Target)
+where
+    C: 'static + Connect,
+{
+    if !target.health_check() {
+        return;
+    }
+
+    let uri = match target.address().parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(error) => {
+            eprintln!("Skipping health check for {:?}: {}", target, error);
+            return;
+        }
+    };
+
+    let healthy = target.health_handle();
+    let timeout = Timeout::new(target.health_timeout(), handle)
+        .expect("could not create health-check timeout");
+
+    let probe = client.get(uri).select2(timeout).then(move |result| {
+        // A response (any status) before the timeout means the backend is up.
+        let alive = match result {
+            Ok(Either::A(_)) => true,
+            _ => false,
+        };
+        healthy.store(alive, ::std::sync::atomic::Ordering::Relaxed);
+        Ok::<(), ()>(())
+    });
+
+    handle.spawn(probe);
+}
+
 fn is_valid_port(v: String) -> Result<(), String> {
     match v.parse::<u16>() {
         Ok(_) => Ok(()),
@@ -86,57 +183,13 @@ fn is_valid_interface(v: String) -> Result<(), String> {
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
-struct Target {
-    name: String,
-    address: String,
-    //FIXME this should be of type regex
-    pattern: Option<String>,
-}
-
-impl Target {
-    fn new<S: Into<String>>(name: S, address: S, pattern: Option<S>) -> Target {
-        Target {
-            name: name.into(),
-            address: address.into(),
-            pattern: pattern.map(Into::into),
-        }
-    }
-}
-
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 struct Config {
     debug: bool,
     listen_addr: SocketAddr,
     targets: Vec<Target>,
 }
 
-fn parse_target<S: Into<String>>(v: S) -> Result<Target, String> {
-    // expected format is "name at target_url if regex_pattern"
-    // only target_url is strictly required
-    let literal_at = " at ";
-    let literal_if = " if ";
-
-    let mut name = None;
-    let mut address = v.into();
-    if let Some(at_pos) = address.find(literal_at) {
-        name = Some(address[0..at_pos].into());
-        address = address[at_pos + literal_at.len()..].into();
-    }
-
-    let mut pattern = None;
-    if let Some(if_pos) = address.find(literal_if) {
-        pattern = Some(address[if_pos + literal_if.len()..].into());
-        address = address[0..if_pos].into();
-    }
-
-    Ok(Target::new(
-        name.unwrap_or(address.clone()),
-        address,
-        pattern,
-    ))
-}
-
 fn is_valid_target(v: String) -> Result<(), String> {
     parse_target(v).map(|_| ())
 }
@@ -154,7 +207,7 @@ fn main() {
             .index(1)
             .help("Add a target to proxy requests to (with optional regular expression matching on path)")
             .validator(is_valid_target)
-            .required(true)
+            .required_unless("config")
             .multiple(true)
         )
         .arg(
@@ -162,8 +215,7 @@ fn main() {
                 .long("port")
                 .short("p")
                 .value_name("PORT")
-                .help("Sets the port that Rocksy should listen on")
-                .default_value("5555")
+                .help("Sets the port that Rocksy should listen on (default 5555)")
                 .validator(is_valid_port)
         )
         .arg(
@@ -171,10 +223,16 @@ fn main() {
                 .long("interface")
                 .short("i")
                 .value_name("INTERFACE")
-                .help("Sets the network interface that Rocksy should listen on")
-                .default_value("127.0.0.1")
+                .help("Sets the network interface that Rocksy should listen on (default 127.0.0.1)")
                 .validator(is_valid_interface)
         )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .short("c")
+                .value_name("FILE")
+                .help("Loads listen settings and targets from a TOML config file (CLI flags override it)")
+        )
         .arg(
             Arg::with_name("debug")
                 .long("debug")
@@ -183,23 +241,54 @@ fn main() {
         )
         .get_matches();
 
-    let debug_on = matches.is_present("debug");
+    // Load the optional config file first; CLI flags below take precedence.
+    let file_config = matches.value_of("config").map(|path| {
+        load_config_file(path).unwrap_or_else(|error| {
+            eprintln!("{}", error);
+            std::process::exit(1);
+        })
+    });
+
+    let debug_on = matches.is_present("debug")
+        || file_config.as_ref().and_then(|c| c.debug).unwrap_or(false);
     if debug_on {
         println!("Parsed command line arguments of: {:?}", matches);
     }
 
-    let listen_addr = format!(
-        "{}:{}",
-        matches.value_of("interface").expect("has default value"),
-        matches.value_of("port").expect("has default value")
-    ).parse::<SocketAddr>()
-        .expect("interface and port should be valid");
-
-    let targets = matches
-        .values_of("targets")
-        .expect("targets is a required argument")
-        .map(|raw| parse_target(raw).expect("target should already be validated"))
-        .collect();
+    let interface = matches
+        .value_of("interface")
+        .map(ToOwned::to_owned)
+        .or_else(|| file_config.as_ref().and_then(|c| c.interface.clone()))
+        .unwrap_or_else(|| "127.0.0.1".to_owned());
+    let port = matches
+        .value_of("port")
+        .map(|p| p.parse::<u16>().expect("port should already be validated"))
+        .or_else(|| file_config.as_ref().and_then(|c| c.port))
+        .unwrap_or(5555);
+
+    // CLI values were checked by the clap validators, but file-provided ones
+    // were not, so report a bad interface and exit rather than panicking.
+    let listen_addr = format!("{}:{}", interface, port)
+        .parse::<SocketAddr>()
+        .unwrap_or_else(|error| {
+            eprintln!("Invalid listen interface '{}': {}", interface, error);
+            std::process::exit(1);
+        });
+
+    // CLI targets win over the file's; the arg is required unless a config is given.
+    let targets = if matches.is_present("targets") {
+        matches
+            .values_of("targets")
+            .expect("targets is present")
+            .map(|raw| parse_target(raw).expect("target should already be validated"))
+            .collect()
+    } else {
+        targets_from_file(file_config.as_ref().expect("required_unless ensures a config file"))
+            .unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            })
+    };
 
     let config = Config {
         debug: debug_on,
@@ -216,49 +305,3 @@ fn main() {
         std::process::exit(1);
     }
 }
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn parse_target_with_everything_succeeds() {
-        let t = parse_target("backend at http://127.0.0.1:9000 if ^/api.*$").unwrap();
-
-        assert_eq!(
-            t,
-            Target::new("backend", "http://127.0.0.1:9000", Some("^/api.*$"))
-        );
-    }
-
-    #[test]
-    fn parse_target_with_no_name_succeeds() {
-        let t = parse_target("http://127.0.0.1:9000 if ^/api.*$").unwrap();
-
-        assert_eq!(
-            t,
-            Target::new(
-                "http://127.0.0.1:9000",
-                "http://127.0.0.1:9000",
-                Some("^/api.*$")
-            )
-        );
-    }
-
-    #[test]
-    fn parse_target_with_no_pattern_succeeds() {
-        let t = parse_target("backend at http://127.0.0.1:9000").unwrap();
-
-        assert_eq!(t, Target::new("backend", "http://127.0.0.1:9000", None));
-    }
-
-    #[test]
-    fn parse_target_with_neither_name_nor_pattern_succeeds() {
-        let t = parse_target("http://127.0.0.1:9000").unwrap();
-
-        assert_eq!(
-            t,
-            Target::new("http://127.0.0.1:9000", "http://127.0.0.1:9000", None)
-        );
-    }
-}
@@ -7,6 +7,8 @@ use hyper::header::Host as HostHeader;
 use hyper::server::Service;
 use std::marker::PhantomData;
 use std::net::IpAddr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
 fn is_hop_header(name: &str) -> bool {
     use unicase::Ascii;
@@ -21,6 +23,7 @@ fn is_hop_header(name: &str) -> bool {
             Ascii::new("Proxy-Authenticate"),
             Ascii::new("Proxy-Authorization"),
             Ascii::new("Te"),
+            Ascii::new("Trailer"),
             Ascii::new("Trailers"),
             Ascii::new("Transfer-Encoding"),
             Ascii::new("Upgrade"),
@@ -34,9 +37,29 @@ fn is_hop_header(name: &str) -> bool {
 ///
 /// [hop-by-hop headers]: http://www.w3.org/Protocols/rfc2616/rfc2616-sec13.html
 fn remove_hop_headers(headers: &Headers) -> Headers {
+    // RFC 7230 §6.1: besides the well-known hop-by-hop headers, any header
+    // named in the `Connection` header's value is connection-scoped and must
+    // not be forwarded. Gather those names first, then strip the union.
+    let connection_named: Vec<String> = headers
+        .get_raw("Connection")
+        .and_then(|raw| raw.one())
+        .map(|value| {
+            String::from_utf8_lossy(value)
+                .split(',')
+                .map(|token| token.trim().to_owned())
+                .filter(|token| !token.is_empty())
+                .collect()
+        })
+        .unwrap_or_else(Vec::new);
+
     headers
         .iter()
-        .filter(|header| !is_hop_header(header.name()))
+        .filter(|header| {
+            !is_hop_header(header.name())
+                && !connection_named
+                    .iter()
+                    .any(|named| named.eq_ignore_ascii_case(header.name()))
+        })
         .collect()
 }
 
@@ -96,6 +119,13 @@ pub struct ReverseProxy<C: Service, B = Body> {
     remote_ip: Option<IpAddr>,
     targets: Vec<Target>,
     debug_on: bool,
+    // Whether the inbound connection reached rocksy over TLS, used to populate
+    // the `proto` forwarding parameters.
+    secure: bool,
+    // Rotated on each request to spread load across equally-matching backends.
+    // Shared across every per-connection `ReverseProxy` so rotation continues
+    // rather than restarting at `0` for each accepted connection.
+    counter: Arc<AtomicUsize>,
     _phantom_data: PhantomData<B>,
 }
 
@@ -106,12 +136,16 @@ impl<C: Service, B> ReverseProxy<C, B> {
         remote_ip: Option<IpAddr>,
         targets: Vec<Target>,
         debug_on: bool,
+        secure: bool,
+        counter: Arc<AtomicUsize>,
     ) -> ReverseProxy<C, B> {
         ReverseProxy {
             client,
             remote_ip,
             targets,
             debug_on,
+            secure,
+            counter,
             _phantom_data: PhantomData,
         }
     }
@@ -133,39 +167,111 @@ impl<C: Service, B> ReverseProxy<C, B> {
             }
         }
 
+        // The inbound scheme and original `Host`, which downstream backends
+        // need to reconstruct absolute URLs. `Host` is captured here, before
+        // the per-target request build strips it.
+        let proto = if self.secure { "https" } else { "http" };
+        let forwarded_host = request.headers().get::<HostHeader>().map(|host| {
+            match host.port() {
+                Some(port) => format!("{}:{}", host.hostname(), port),
+                None => host.hostname().to_owned(),
+            }
+        });
+
+        // Emit the standardized RFC 7239 `Forwarded` header, appending a new
+        // element rather than replacing any an upstream proxy already sent.
+        let mut params = Vec::new();
+        if let Some(ip) = self.remote_ip {
+            // IPv6 addresses must be bracketed and quoted; IPv4 is bare.
+            match ip {
+                IpAddr::V4(ip) => params.push(format!("for={}", ip)),
+                IpAddr::V6(ip) => params.push(format!("for=\"[{}]\"", ip)),
+            }
+        }
+        params.push(format!("proto={}", proto));
+        if let Some(ref host) = forwarded_host {
+            params.push(format!("host={}", host));
+        }
+        let element = params.join(";");
+        let forwarded = match request.headers().get_raw("Forwarded").and_then(|raw| raw.one()) {
+            Some(existing) => format!("{}, {}", String::from_utf8_lossy(existing), element),
+            None => element,
+        };
+        request.headers_mut().set_raw("Forwarded", forwarded);
+
+        // Also emit the de-facto `X-Forwarded-*` headers for backends that do
+        // not parse `Forwarded`.
+        request.headers_mut().set_raw("X-Forwarded-Proto", proto.to_owned());
+        if let Some(host) = forwarded_host {
+            request.headers_mut().set_raw("X-Forwarded-Host", host);
+        }
+
         request
     }
 
-    fn determine_target(&self, request: &Request<B>) -> Option<&Target> {
-        self.targets
-            .iter()
-            .find(|&t| t.valid_for(request.uri().path()))
-    }
+    /// The healthy backends that match this request, rotated so that requests
+    /// are spread round-robin across equally-matching targets. The returned
+    /// order is also the order failover falls through on dispatch errors.
+    fn healthy_candidates(&self, request: &Request<B>) -> Vec<Target> {
+        let path = request.uri().path();
+        let host = request.headers().get::<HostHeader>().map(|h| h.hostname());
 
-    fn point_request_at_target(&self, target: &Target, mut request: Request<B>) -> Request<B> {
-        let mut target_uri = target.address().to_owned();
+        let mut matching: Vec<Target> = self.targets
+            .iter()
+            .filter(|t| t.valid_for(path, host))
+            .filter(|t| t.is_healthy())
+            .cloned()
+            .collect();
 
-        target_uri.push_str(request.uri().path());
-        if let Some(query) = request.uri().query() {
-            target_uri.push_str("?");
-            target_uri.push_str(query);
+        if !matching.is_empty() {
+            let start = self.counter.fetch_add(1, Ordering::Relaxed) % matching.len();
+            matching.rotate_left(start);
         }
 
-        if let Some(target) = target_uri.parse::<Uri>().ok() {
-            request.headers_mut().remove::<HostHeader>();
-            request.set_uri(target);
-        } else {
-            eprintln!("Failed to build request url for {:?}", &request)
-        }
+        matching
+    }
+}
 
-        request
+/// Build the request to forward to `target`, reusing the incoming method and
+/// headers. The `Host` header is dropped so the target picks its own.
+///
+/// Returns `None` if the assembled target URL does not parse; the caller treats
+/// that like a dispatch error and fails over to the next backend rather than
+/// tearing down request handling.
+fn build_request_for_target<B>(
+    target: &Target,
+    method: &hyper::Method,
+    headers: &Headers,
+    path: &str,
+    query: &Option<String>,
+    body: B,
+) -> Option<Request<B>> {
+    let mut target_uri = target.address().to_owned();
+    target_uri.push_str(path);
+    if let Some(ref query) = *query {
+        target_uri.push_str("?");
+        target_uri.push_str(query);
     }
+
+    let uri = match target_uri.parse::<Uri>() {
+        Ok(uri) => uri,
+        Err(error) => {
+            eprintln!("Failed to build request url '{}': {}", target_uri, error);
+            return None;
+        }
+    };
+
+    let mut request = Request::new(method.clone(), uri);
+    *request.headers_mut() = headers.clone();
+    request.headers_mut().remove::<HostHeader>();
+    request.set_body(body);
+    Some(request)
 }
 
 impl<C, B> Service for ReverseProxy<C, B>
 where
-    B: 'static,
-    C: Service<Request = Request<B>, Response = Response<B>>,
+    B: 'static + Default,
+    C: 'static + Clone + Service<Request = Request<B>, Response = Response<B>>,
     C::Error: 'static + ::std::fmt::Display,
     C::Future: 'static,
 {
@@ -186,37 +292,234 @@ where
         );
 
         let proxied_request = self.create_proxied_request(request);
-        if let Some(target) = self.determine_target(&proxied_request) {
-            if self.debug_on {
-                println!("Determined target of {:?}", target);
+        let candidates = self.healthy_candidates(&proxied_request);
+
+        if candidates.is_empty() {
+            // no healthy target matches this request - should respond with 404
+            let response = Response::new().with_status(StatusCode::NotFound);
+            log_request_response(&incoming, "Rocksy (fallback)", response.status());
+            return Box::new(Ok(response).into_future());
+        }
+
+        if self.debug_on {
+            println!("Candidate targets (in failover order) are {:?}", candidates);
+        }
+
+        // Split the request into the parts we need to rebuild it for each
+        // candidate; the body is only replayed to the first backend tried.
+        let path = proxied_request.uri().path().to_owned();
+        let query = proxied_request.uri().query().map(|q| q.to_owned());
+        let (method, _uri, _version, headers, body) = proxied_request.deconstruct();
+
+        dispatch_to_targets(
+            self.client.clone(),
+            candidates,
+            0,
+            method,
+            headers,
+            path,
+            query,
+            body,
+            incoming,
+            self.debug_on,
+        )
+    }
+}
+
+/// Try `targets[index]`, falling through to the next backend if dispatch
+/// errors, until one responds or the pool is exhausted (returning a 500).
+#[cfg_attr(feature = "cargo-clippy", allow(too_many_arguments))]
+fn dispatch_to_targets<C, B>(
+    client: C,
+    targets: Vec<Target>,
+    index: usize,
+    method: hyper::Method,
+    headers: Headers,
+    path: String,
+    query: Option<String>,
+    body: B,
+    incoming: String,
+    debug_on: bool,
+) -> Box<Future<Item = Response<B>, Error = hyper::Error>>
+where
+    B: 'static + Default,
+    C: 'static + Clone + Service<Request = Request<B>, Response = Response<B>>,
+    C::Error: 'static + ::std::fmt::Display,
+    C::Future: 'static,
+{
+    let target = targets[index].clone();
+    let request = match build_request_for_target(&target, &method, &headers, &path, &query, body) {
+        Some(request) => request,
+        None => {
+            // The target address is unusable; treat it like a dispatch failure
+            // and fall through to the next healthy backend.
+            if index + 1 < targets.len() {
+                return dispatch_to_targets(
+                    client,
+                    targets,
+                    index + 1,
+                    method,
+                    headers,
+                    path,
+                    query,
+                    B::default(),
+                    incoming,
+                    debug_on,
+                );
             }
-            let pointed_request = self.point_request_at_target(target, proxied_request);
+            let response = Response::new().with_status(StatusCode::InternalServerError);
+            log_request_response(&incoming, target.name(), response.status());
+            return Box::new(Ok(response).into_future());
+        }
+    };
+
+    if debug_on {
+        println!("Making a request of {:?} to {:?}", &request, &target);
+    }
 
-            if self.debug_on {
-                println!("Making a request of {:?}", &pointed_request);
+    let next_client = client.clone();
+    Box::new(client.call(request).then(move |result| -> Box<Future<Item = Response<B>, Error = hyper::Error>> {
+        match result {
+            Ok(response) => {
+                log_request_response(&incoming, target.name(), response.status());
+                Box::new(Ok(create_proxied_response(response)).into_future())
+            }
+            Err(error) => {
+                eprintln!("Failed to proxy request to {:?}! {}", target, error);
+                if index + 1 < targets.len() {
+                    // A subsequent backend may still be able to serve this; note
+                    // that the body is not replayed to the fallback target.
+                    dispatch_to_targets(
+                        next_client,
+                        targets,
+                        index + 1,
+                        method,
+                        headers,
+                        path,
+                        query,
+                        B::default(),
+                        incoming,
+                        debug_on,
+                    )
+                } else {
+                    let response = Response::new().with_status(StatusCode::InternalServerError);
+                    log_request_response(&incoming, target.name(), response.status());
+                    Box::new(Ok(response).into_future())
+                }
             }
+        }
+    }))
+}
 
-            // clone to allow moving target into closure
-            let target = target.clone();
-            Box::new(self.client.call(pointed_request).then(move |response| {
-                Ok(match response {
-                    Ok(response) => {
-                        log_request_response(&incoming, target.name().as_ref(), response.status());
-                        create_proxied_response(response)
-                    }
-                    Err(error) => {
-                        eprintln!("Failed to proxy request to {:?}! {}", target, error);
-                        Response::new().with_status(StatusCode::InternalServerError)
-                    }
-                })
-            }))
-        } else {
-            // no valid target for this request - should respond with 404
-            let response = Response::new().with_status(StatusCode::NotFound);
-            log_request_response(&incoming, "Rocksy (fallback)", response.status());
-            Box::new(Ok(response).into_future())
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::Future;
+    use hyper::{Body, Method};
+    use hyper::header::Host as HostHeader;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    /// A stand-in client; `create_proxied_request` never dispatches through it.
+    #[derive(Clone)]
+    struct DummyClient;
+
+    impl Service for DummyClient {
+        type Request = Request<Body>;
+        type Response = Response<Body>;
+        type Error = hyper::Error;
+        type Future = Box<Future<Item = Response<Body>, Error = hyper::Error>>;
+
+        fn call(&self, _request: Self::Request) -> Self::Future {
+            Box::new(Ok(Response::new()).into_future())
         }
     }
+
+    fn proxy(remote_ip: Option<IpAddr>, secure: bool) -> ReverseProxy<DummyClient, Body> {
+        ReverseProxy::new(DummyClient, remote_ip, vec![], false, secure, Arc::new(AtomicUsize::new(0)))
+    }
+
+    fn request_to(host: &str) -> Request<Body> {
+        let mut request = Request::new(Method::Get, "http://example/".parse().unwrap());
+        request.headers_mut().set(HostHeader::new(host.to_owned(), None));
+        request
+    }
+
+    fn raw_header(headers: &Headers, name: &str) -> Option<String> {
+        headers
+            .get_raw(name)
+            .and_then(|raw| raw.one())
+            .map(|value| String::from_utf8_lossy(value).into_owned())
+    }
+
+    #[test]
+    fn forwarded_is_emitted_with_ipv4_and_proto_and_host() {
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 195));
+        let out = proxy(Some(ip), false).create_proxied_request(request_to("example.com"));
+
+        assert_eq!(
+            raw_header(out.headers(), "Forwarded"),
+            Some("for=203.0.113.195;proto=http;host=example.com".to_owned())
+        );
+        assert_eq!(raw_header(out.headers(), "X-Forwarded-Proto"), Some("http".to_owned()));
+        assert_eq!(raw_header(out.headers(), "X-Forwarded-Host"), Some("example.com".to_owned()));
+    }
+
+    #[test]
+    fn forwarded_brackets_and_quotes_ipv6() {
+        let ip = IpAddr::V6(Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1));
+        let out = proxy(Some(ip), true).create_proxied_request(request_to("example.com"));
+
+        assert_eq!(
+            raw_header(out.headers(), "Forwarded"),
+            Some("for=\"[2001:db8::1]\";proto=https;host=example.com".to_owned())
+        );
+        assert_eq!(raw_header(out.headers(), "X-Forwarded-Proto"), Some("https".to_owned()));
+    }
+
+    #[test]
+    fn forwarded_appends_to_existing_value() {
+        let ip = IpAddr::V4(Ipv4Addr::new(203, 0, 113, 195));
+        let mut request = request_to("example.com");
+        request.headers_mut().set_raw("Forwarded", "for=198.51.100.17;proto=https");
+
+        let out = proxy(Some(ip), false).create_proxied_request(request);
+
+        assert_eq!(
+            raw_header(out.headers(), "Forwarded"),
+            Some("for=198.51.100.17;proto=https, for=203.0.113.195;proto=http;host=example.com".to_owned())
+        );
+    }
+
+    #[test]
+    fn static_hop_headers_are_stripped() {
+        let mut headers = Headers::new();
+        headers.set_raw("Connection", "keep-alive");
+        headers.set_raw("Keep-Alive", "timeout=5");
+        headers.set_raw("Trailer", "Expires");
+        headers.set_raw("X-Keep", "yes");
+
+        let out = remove_hop_headers(&headers);
+
+        assert!(out.get_raw("Connection").is_none());
+        assert!(out.get_raw("Keep-Alive").is_none());
+        assert!(out.get_raw("Trailer").is_none());
+        assert_eq!(raw_header(&out, "X-Keep"), Some("yes".to_owned()));
+    }
+
+    #[test]
+    fn connection_named_headers_are_stripped() {
+        let mut headers = Headers::new();
+        headers.set_raw("Connection", "X-Private, X-Other");
+        headers.set_raw("X-Private", "secret");
+        headers.set_raw("X-Other", "gone");
+        headers.set_raw("X-Public", "kept");
+
+        let out = remove_hop_headers(&headers);
+
+        assert!(out.get_raw("X-Private").is_none());
+        assert!(out.get_raw("X-Other").is_none());
+        assert_eq!(raw_header(&out, "X-Public"), Some("kept".to_owned()));
+    }
 }
 
 fn log_request_response(incoming: &str, responder: &str, status: StatusCode) {